@@ -22,8 +22,62 @@
 //! - Completely safe implementation (no `unsafe` used).
 
 
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::hash_map::Entry::{Vacant, Occupied};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/// Set on the leading position word of a shorthand to mark it as a
+/// backreference rather than an inline value.
+const SHORTHAND_BIT: u64 = 1 << 63;
+
+/// Constant word at the very end of a finalized blob, used to sanity-check
+/// that what is being opened is actually one of our datasets.
+const MAGIC: u32 = 0x8a16_6afb;
+
+/// Size in bytes of the fixed-width footer: the object/string table entry
+/// counts and addresses (`u64` each), the four_cc, and the magic word.
+const FOOTER_SIZE: u64 = 8 + 8 + 8 + 8 + 4 + 4;
+
+fn shorthand_hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Maps an already-encoded value to the byte position of its inline copy.
+/// Keyed on the value's type and hash, but with a bucket per key so that a
+/// hash collision between two distinct values is resolved by an actual
+/// equality check instead of silently aliasing them.
+type ShorthandTable = HashMap<(TypeId, u64), Vec<(Box<dyn Any>, u64)>>;
+
+/// Maps an interned value to the dense index assigned to it. Shares the
+/// type+hash keying and per-bucket equality check of `ShorthandTable`.
+type InterningTable = HashMap<(TypeId, u64), Vec<(Box<dyn Any>, u32)>>;
+
+/// A leaf value that can live in the interning table: a small, immutable,
+/// frequently-repeated value such as a symbol, type name or source-file path.
+/// Interning assigns each distinct value a dense index and writes its bytes
+/// exactly once into a side region, so repeated uses cost only the index.
+pub trait Interned: Hash + Eq + Clone + 'static {
+    /// The byte representation written into the interning side region.
+    fn intern_bytes(&self) -> Vec<u8>;
+
+    /// Reconstruct a value from the bytes produced by `intern_bytes`.
+    fn from_intern_bytes(bytes: &[u8]) -> Self;
+}
+
+impl Interned for String {
+    fn intern_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_intern_bytes(bytes: &[u8]) -> String {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
 
 
 //=-----------------------------------------------------------------------------
@@ -35,60 +89,269 @@ pub struct ObjectUid(u64);
 #[derive(Clone, Copy, Eq, PartialEq, Hash)]
 pub struct ObjectTableIndex(u32);
 
-/// Something that can be encoded given a certain context ECX.
-pub trait Encodable<ECX> {
+/// Something that can be encoded given a certain context ECX. The `E` type
+/// parameter is the error type of the underlying `Encoder`, which is threaded
+/// through so that an I/O failure can be propagated instead of panicking.
+pub trait Encodable<ECX, E> {
     fn encode<'ecx, 'encodable>(&'encodable self,
-                                session: &mut EncodingContext<'ecx, ECX>)
+                                session: &mut EncodingContext<'ecx, ECX, E>)
+        -> Result<(), E>
         where 'encodable: 'ecx;
 }
 
 /// Values implementing this trait will only be emitted once during an
 /// EncodingSession. Other values referencing them only store an address where
 /// actual object can be found within the encoded data.
-pub trait EncodableObject<ECX> : Encodable<ECX> {
+pub trait EncodableObject<ECX, E> : Encodable<ECX, E> {
     fn object_uid(&self) -> ObjectUid;
 
     fn encode_contents<'encodable, 'ecx>(&'encodable self,
-                                         session: &mut EncodingContext<'ecx, ECX>)
+                                         session: &mut EncodingContext<'ecx, ECX, E>)
+        -> Result<(), E>
         where 'encodable: 'ecx;
 }
 
 pub trait Encoder {
+    /// The kind of error this encoder can report, e.g. an `io::Error` for a
+    /// file-backed encoder.
+    type Error;
+
     fn position(&self) -> u64;
-    fn finalize(self: Box<Self>, four_cc: [u8; 4], object_table_address: u64);
 
-    fn emit_u32(&mut self, value: u32);
-    fn emit_u64(&mut self, value: u64);
-    // TODO: Add emit_* methods of other primitive and composite types
+    /// Flush and close the encoder. The self-describing footer (object/string
+    /// tables, four_cc and magic word) has already been emitted through the
+    /// `emit_*` methods by the time this is called, so an implementor must only
+    /// flush any buffered output here and write nothing more.
+    fn finalize(self: Box<Self>) -> Result<(), Self::Error>;
+
+    /// The single byte-level primitive an implementor has to provide. All the
+    /// other `emit_*` methods are built on top of it.
+    fn emit_u8(&mut self, value: u8) -> Result<(), Self::Error>;
+
+    // Fixed-width, little-endian integers. Used where a value has to be
+    // locatable by seeking a known distance rather than scanned for, e.g. the
+    // entries of the object table.
+    fn emit_u16(&mut self, value: u16) -> Result<(), Self::Error> {
+        self.emit_u8(value as u8)?;
+        self.emit_u8((value >> 8) as u8)
+    }
+
+    fn emit_u32(&mut self, value: u32) -> Result<(), Self::Error> {
+        for i in 0 .. 4 {
+            self.emit_u8((value >> (i * 8)) as u8)?;
+        }
+        Ok(())
+    }
+
+    fn emit_u64(&mut self, value: u64) -> Result<(), Self::Error> {
+        for i in 0 .. 8 {
+            self.emit_u8((value >> (i * 8)) as u8)?;
+        }
+        Ok(())
+    }
+
+    fn emit_u128(&mut self, value: u128) -> Result<(), Self::Error> {
+        for i in 0 .. 16 {
+            self.emit_u8((value >> (i * 8)) as u8)?;
+        }
+        Ok(())
+    }
+
+    /// Emit `value` in LEB128 format: seven bits of the value per byte,
+    /// least-significant group first, with the high bit (0x80) set on every
+    /// byte but the last. Small values take only a single byte.
+    fn emit_uleb128(&mut self, mut value: u64) -> Result<(), Self::Error> {
+        loop {
+            let mut byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value != 0 {
+                byte |= 0x80;
+            }
+            self.emit_u8(byte)?;
+            if value == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a signed value as LEB128 after zigzag-mapping it, so that
+    /// small-magnitude negatives stay short.
+    fn emit_ileb128(&mut self, value: i64) -> Result<(), Self::Error> {
+        self.emit_uleb128(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    fn emit_usize(&mut self, value: usize) -> Result<(), Self::Error> {
+        self.emit_uleb128(value as u64)
+    }
+
+    fn emit_isize(&mut self, value: isize) -> Result<(), Self::Error> {
+        self.emit_ileb128(value as i64)
+    }
+
+    fn emit_i8(&mut self, value: i8) -> Result<(), Self::Error> {
+        self.emit_ileb128(value as i64)
+    }
+
+    fn emit_i16(&mut self, value: i16) -> Result<(), Self::Error> {
+        self.emit_ileb128(value as i64)
+    }
+
+    fn emit_i32(&mut self, value: i32) -> Result<(), Self::Error> {
+        self.emit_ileb128(value as i64)
+    }
+
+    fn emit_i64(&mut self, value: i64) -> Result<(), Self::Error> {
+        self.emit_ileb128(value)
+    }
+
+    fn emit_i128(&mut self, value: i128) -> Result<(), Self::Error> {
+        self.emit_u128(((value << 1) ^ (value >> 127)) as u128)
+    }
+
+    fn emit_bool(&mut self, value: bool) -> Result<(), Self::Error> {
+        self.emit_u8(value as u8)
+    }
+
+    fn emit_f32(&mut self, value: f32) -> Result<(), Self::Error> {
+        self.emit_u32(value.to_bits())
+    }
+
+    fn emit_f64(&mut self, value: f64) -> Result<(), Self::Error> {
+        self.emit_u64(value.to_bits())
+    }
+
+    /// Emit a byte slice prefixed by its LEB128-encoded length.
+    fn emit_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.emit_uleb128(bytes.len() as u64)?;
+        for &byte in bytes {
+            self.emit_u8(byte)?;
+        }
+        Ok(())
+    }
+
+    fn emit_str(&mut self, value: &str) -> Result<(), Self::Error> {
+        self.emit_bytes(value.as_bytes())
+    }
 }
 
-pub struct EncodingContext<'ctx, ECX: 'ctx> {
-    encoder: &'ctx mut Encoder,
+pub struct EncodingContext<'ctx, ECX: 'ctx, E: 'ctx> {
+    encoder: &'ctx mut dyn Encoder<Error = E>,
     object_table_indices: &'ctx mut HashMap<ObjectUid, ObjectTableIndex>,
     object_table: &'ctx mut Vec<u64>,
-    delayed_writes: &'ctx mut Vec<(&'ctx EncodableObject<ECX>, ObjectTableIndex)>,
+    delayed_writes: &'ctx mut Vec<(&'ctx dyn EncodableObject<ECX, E>, ObjectTableIndex)>,
+    shorthands: &'ctx mut ShorthandTable,
+    interned_indices: &'ctx mut InterningTable,
+    interned_blobs: &'ctx mut Vec<Vec<u8>>,
     pub extra: &'ctx mut ECX
 }
 
-impl<'sess, ECX: 'sess> EncodingContext<'sess, ECX> {
+impl<'sess, ECX: 'sess, E: 'sess> EncodingContext<'sess, ECX, E> {
 
-    pub fn encoder(&mut self) -> &mut Encoder {
+    pub fn encoder(&mut self) -> &mut dyn Encoder<Error = E> {
         &mut *self.encoder
     }
 
-    pub fn encode_object<'object, O>(&mut self, object: &'object O)
-        where O: EncodableObject<ECX>,
+    pub fn encode_object<'object, O>(&mut self, object: &'object O) -> Result<(), E>
+        where O: EncodableObject<ECX, E>,
               'object: 'sess
     {
         let object_uid = EncodableObject::object_uid(object);
         let (object_table_index, is_new) = self.get_object_table_index(object_uid);
 
-        self.encoder().emit_u32(object_table_index.0);
+        self.encoder().emit_uleb128(object_table_index.0 as u64)?;
 
         if is_new {
             self.enqueue_object_encoding(object_table_index,
-                                         object as &'sess EncodableObject<ECX>);
+                                         object as &'sess dyn EncodableObject<ECX, E>);
         }
+
+        Ok(())
+    }
+
+    /// Encode a recursively-shared value with deduplication but without a
+    /// pre-reserved object table slot. The first time a given value is seen its
+    /// contents are written inline (behind a leading tag word with the
+    /// shorthand bit cleared) and its byte position is remembered. Every later
+    /// encode of an equal value emits only that position with the shorthand bit
+    /// set, so the decoder can seek back to the inline copy.
+    pub fn encode_shorthand<'object, T>(&mut self, value: &'object T) -> Result<(), E>
+        where T: Encodable<ECX, E> + Hash + Eq + Clone + 'static,
+              'object: 'sess
+    {
+        let key = (TypeId::of::<T>(), shorthand_hash(value));
+
+        // A hash hit is only a backreference if the stored value actually
+        // equals this one; otherwise it is a collision and we fall through to
+        // writing a fresh inline copy.
+        let mut backreference = None;
+        if let Some(bucket) = self.shorthands.get(&key) {
+            for &(ref stored, position) in bucket {
+                if stored.downcast_ref::<T>() == Some(value) {
+                    backreference = Some(position);
+                    break;
+                }
+            }
+        }
+
+        if let Some(position) = backreference {
+            return self.encoder().emit_uleb128(position | SHORTHAND_BIT);
+        }
+
+        let position = self.encoder.position();
+
+        // Leading tag word with the shorthand bit cleared, followed by the
+        // value written inline.
+        self.encoder().emit_uleb128(0)?;
+        Encodable::encode(value, self)?;
+
+        self.shorthands
+            .entry(key)
+            .or_default()
+            .push((Box::new(value.clone()), position));
+
+        Ok(())
+    }
+
+    /// Emit an interned leaf value as just its dense table index. Each distinct
+    /// value is assigned an index on first sight and its bytes are written once
+    /// into a side region during `finalize`; repeated values cost only the
+    /// LEB128 index. Works for any `Interned` leaf such as identifiers, type
+    /// names or path strings.
+    pub fn emit_interned<T: Interned>(&mut self, value: &T) -> Result<(), E> {
+        let key = (TypeId::of::<T>(), shorthand_hash(value));
+
+        // As with shorthands, a hash hit is only a real hit if the stored value
+        // actually equals this one.
+        let mut found = None;
+        if let Some(bucket) = self.interned_indices.get(&key) {
+            for &(ref stored, index) in bucket {
+                if stored.downcast_ref::<T>() == Some(value) {
+                    found = Some(index);
+                    break;
+                }
+            }
+        }
+
+        let index = match found {
+            Some(index) => index,
+            None => {
+                let index = self.interned_blobs.len() as u32;
+                self.interned_blobs.push(value.intern_bytes());
+                self.interned_indices
+                    .entry(key)
+                    .or_default()
+                    .push((Box::new(value.clone()), index));
+                index
+            }
+        };
+
+        self.encoder().emit_uleb128(index as u64)
+    }
+
+    /// Convenience wrapper around `emit_interned` for string leaves.
+    pub fn emit_interned_str(&mut self, value: &str) -> Result<(), E> {
+        self.emit_interned(&value.to_string())
     }
 
     fn get_object_table_index(&mut self, object_uid: ObjectUid) -> (ObjectTableIndex, bool) {
@@ -98,13 +361,13 @@ impl<'sess, ECX: 'sess> EncodingContext<'sess, ECX> {
                 // TODO: make this a safe conversion
                 let index = ObjectTableIndex(self.object_table.len() as u32);
                 vacant.insert(index);
-                self.object_table.push(u64::max_value());
+                self.object_table.push(u64::MAX);
                 (index, true)
             }
         }
     }
 
-    fn write_enqueued_objects(&mut self) {
+    fn write_enqueued_objects(&mut self) -> Result<(), E> {
         loop {
             // encoding objects might add more to this queue, so we can't do
             // this in a for loop
@@ -113,41 +376,49 @@ impl<'sess, ECX: 'sess> EncodingContext<'sess, ECX> {
             match item {
                 Some((object, object_table_index)) => {
                     let position = self.encoder.position();
-                    object.encode_contents(self);
+                    object.encode_contents(self)?;
                     // Now that we know the address, write it to the table
                     self.object_table[object_table_index.0 as usize] = position;
                 },
                 None => break,
             }
         }
+
+        Ok(())
     }
 
     fn enqueue_object_encoding(&mut self,
                                object_table_index: ObjectTableIndex,
-                               encodable: &'sess EncodableObject<ECX>) {
+                               encodable: &'sess dyn EncodableObject<ECX, E>) {
         self.delayed_writes.push((encodable, object_table_index));
     }
 }
 
-pub struct EncodingSession<ECX> {
-    encoder: Box<Encoder>,
+pub struct EncodingSession<ECX, E> {
+    encoder: Box<dyn Encoder<Error = E>>,
     object_table_indices: HashMap<ObjectUid, ObjectTableIndex>,
     object_table: Vec<u64>,
+    shorthands: ShorthandTable,
+    interned_indices: InterningTable,
+    interned_blobs: Vec<Vec<u8>>,
     pub context: ECX
 }
 
-impl<ECX> EncodingSession<ECX> {
+impl<ECX, E> EncodingSession<ECX, E> {
 
-    pub fn new<E: Encoder+'static>(encoder: E, ecx: ECX) -> EncodingSession<ECX> {
+    pub fn new<En: Encoder<Error = E> + 'static>(encoder: En, ecx: ECX) -> EncodingSession<ECX, E> {
         EncodingSession {
             encoder: Box::new(encoder),
             object_table_indices: HashMap::new(),
             object_table: Vec::new(),
+            shorthands: HashMap::new(),
+            interned_indices: HashMap::new(),
+            interned_blobs: Vec::new(),
             context: ecx
         }
     }
 
-    pub fn encode<T: Encodable<ECX>>(&mut self, encodable: &T) {
+    pub fn encode<T: Encodable<ECX, E>>(&mut self, encodable: &T) -> Result<(), E> {
         let mut delayed_writes = Vec::new();
 
         let mut context = EncodingContext {
@@ -155,21 +426,46 @@ impl<ECX> EncodingSession<ECX> {
             object_table_indices: &mut self.object_table_indices,
             object_table: &mut self.object_table,
             delayed_writes: &mut delayed_writes,
+            shorthands: &mut self.shorthands,
+            interned_indices: &mut self.interned_indices,
+            interned_blobs: &mut self.interned_blobs,
             extra: &mut self.context,
         };
 
-        encodable.encode(&mut context);
-        context.write_enqueued_objects();
+        encodable.encode(&mut context)?;
+        context.write_enqueued_objects()
     }
 
-    pub fn finalize(mut self, four_cc: [u8; 4]) {
+    pub fn finalize(mut self, four_cc: [u8; 4]) -> Result<(), E> {
         let object_table_address = self.encoder.position();
+        let object_entry_count = self.object_table.len() as u64;
+
+        for object_table_entry in &self.object_table {
+            self.encoder.emit_u64(*object_table_entry)?;
+        }
 
-        for object_table_entry in self.object_table {
-            self.encoder.emit_u64(object_table_entry);
+        // The interning side region: the bytes of each distinct value, written
+        // exactly once, in index order.
+        let interning_table_address = self.encoder.position();
+        let interned_entry_count = self.interned_blobs.len() as u64;
+
+        for interned_blob in &self.interned_blobs {
+            self.encoder.emit_bytes(interned_blob)?;
+        }
+
+        // Fixed-width footer: even though the body uses variable-length LEB128,
+        // these fields have a known size so they can be located by seeking a
+        // constant distance back from the end of the blob.
+        self.encoder.emit_u64(object_entry_count)?;
+        self.encoder.emit_u64(object_table_address)?;
+        self.encoder.emit_u64(interned_entry_count)?;
+        self.encoder.emit_u64(interning_table_address)?;
+        for &byte in &four_cc {
+            self.encoder.emit_u8(byte)?;
         }
+        self.encoder.emit_u32(MAGIC)?;
 
-        self.encoder.finalize(four_cc, object_table_address);
+        self.encoder.finalize()
     }
 }
 
@@ -179,151 +475,818 @@ impl<ECX> EncodingSession<ECX> {
 //=-----------------------------------------------------------------------------
 // TODO: The decoding API is still in flux.
 
-pub trait Decodable<DCX> {
-    fn decode(context: &mut DecodingContext<DCX>) -> Self;
+pub trait Decodable<DCX, E> : Sized {
+    fn decode(context: &mut DecodingContext<DCX, E>) -> Result<Self, E>;
 }
 
-pub trait DecodableObject<DCX> : Decodable<DCX> {
-    fn decode_contents(context: &mut DecodingContext<DCX>) -> Self;
+pub trait DecodableObject<DCX, E> : Decodable<DCX, E> {
+    fn decode_contents(context: &mut DecodingContext<DCX, E>) -> Result<Self, E>;
 }
 
 pub trait Decoder {
-    fn set_position(&mut self, position: u64);
+    /// The kind of error this decoder can report, e.g. a truncated input or a
+    /// failed consistency check reported via `error`.
+    type Error;
+
+    /// Construct an error of this decoder's error type from a message. Used by
+    /// the framework to signal things like an out-of-range object table index.
+    fn error(&mut self, err: &str) -> Self::Error;
+
+    fn set_position(&mut self, position: u64) -> Result<(), Self::Error>;
     fn position(&self) -> u64;
-    fn read_u32(&mut self) -> u32;
-    fn read_u64(&mut self) -> u64;
+
+    /// The total number of bytes available, used to locate the footer by
+    /// seeking back from the end.
+    fn len(&self) -> u64;
+
+    /// Whether there are no bytes available at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The single byte-level primitive an implementor has to provide. All the
+    /// other `read_*` methods are built on top of it.
+    fn read_u8(&mut self) -> Result<u8, Self::Error>;
+
+    fn read_u16(&mut self) -> Result<u16, Self::Error> {
+        let lo = self.read_u8()? as u16;
+        let hi = self.read_u8()? as u16;
+        Ok(lo | (hi << 8))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Self::Error> {
+        let mut value = 0;
+        for i in 0 .. 4 {
+            value |= (self.read_u8()? as u32) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Self::Error> {
+        let mut value = 0;
+        for i in 0 .. 8 {
+            value |= (self.read_u8()? as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    fn read_u128(&mut self) -> Result<u128, Self::Error> {
+        let mut value = 0;
+        for i in 0 .. 16 {
+            value |= (self.read_u8()? as u128) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    /// Read a LEB128-encoded value: consume bytes until one without the high
+    /// bit (0x80) is seen, shifting each 7-bit group left by `7*i`.
+    fn read_uleb128(&mut self) -> Result<u64, Self::Error> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            // A well-formed u64 needs at most ten 7-bit groups; anything longer
+            // is malformed input and would overflow the shift.
+            if shift >= 64 {
+                return Err(self.error("overlong LEB128 sequence"));
+            }
+        }
+        Ok(value)
+    }
+
+    /// Read a zigzag-mapped signed LEB128 value, reversing `emit_ileb128`.
+    fn read_ileb128(&mut self) -> Result<i64, Self::Error> {
+        let value = self.read_uleb128()?;
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    fn read_usize(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.read_uleb128()? as usize)
+    }
+
+    fn read_isize(&mut self) -> Result<isize, Self::Error> {
+        Ok(self.read_ileb128()? as isize)
+    }
+
+    fn read_i8(&mut self) -> Result<i8, Self::Error> {
+        Ok(self.read_ileb128()? as i8)
+    }
+
+    fn read_i16(&mut self) -> Result<i16, Self::Error> {
+        Ok(self.read_ileb128()? as i16)
+    }
+
+    fn read_i32(&mut self) -> Result<i32, Self::Error> {
+        Ok(self.read_ileb128()? as i32)
+    }
+
+    fn read_i64(&mut self) -> Result<i64, Self::Error> {
+        self.read_ileb128()
+    }
+
+    fn read_i128(&mut self) -> Result<i128, Self::Error> {
+        let value = self.read_u128()?;
+        Ok(((value >> 1) as i128) ^ -((value & 1) as i128))
+    }
+
+    fn read_bool(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    fn read_f32(&mut self) -> Result<f32, Self::Error> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, Self::Error> {
+        Ok(f64::from_bits(self.read_u64()?))
+    }
+
+    /// Read a LEB128 length prefix followed by that many bytes.
+    fn read_bytes(&mut self) -> Result<Vec<u8>, Self::Error> {
+        let len = self.read_uleb128()? as usize;
+        let mut bytes = Vec::with_capacity(len);
+        for _ in 0 .. len {
+            bytes.push(self.read_u8()?);
+        }
+        Ok(bytes)
+    }
+
+    fn read_str(&mut self) -> Result<String, Self::Error> {
+        let bytes = self.read_bytes()?;
+        String::from_utf8(bytes).map_err(|_| self.error("invalid utf-8 in string"))
+    }
 }
 
-pub struct DecodingContext<'ctx, DCX> {
-    decoder: &'ctx mut Decoder,
+pub struct DecodingContext<'ctx, DCX, E> {
+    decoder: &'ctx mut dyn Decoder<Error = E>,
     object_table: Vec<u64>,
+    // Caches the handle of each object that has already been decoded, so that a
+    // shared DAG of references is decoded once rather than exponentially. The
+    // handle is inserted only after `decode_contents` returns, so a genuinely
+    // cyclic graph (an object that references itself transitively) is not
+    // supported and would not terminate.
+    object_cache: HashMap<ObjectTableIndex, Rc<dyn Any>>,
+    // The interned value blobs, indexed by the dense index written by
+    // `emit_interned`.
+    interned_blobs: Vec<Vec<u8>>,
     pub extra: DCX,
 }
 
-impl<'ctx, DCX> DecodingContext<'ctx, DCX> {
+impl<'ctx, DCX, E> DecodingContext<'ctx, DCX, E> {
 
-    pub fn decoder(&mut self) -> &mut Decoder {
+    pub fn decoder(&mut self) -> &mut dyn Decoder<Error = E> {
         self.decoder
     }
 
-    pub fn decode_object<T:DecodableObject<DCX>>(&mut self) -> T {
-        let object_table_index = self.decoder.read_u32();
+    pub fn decode_object<T: DecodableObject<DCX, E> + 'static>(&mut self) -> Result<Rc<T>, E> {
+        let object_table_index = self.decoder.read_uleb128()?;
+
+        if object_table_index as usize >= self.object_table.len() {
+            return Err(self.decoder.error("object_table_index out of bounds"));
+        }
+
+        let index = ObjectTableIndex(object_table_index as u32);
+
+        // On a cache hit hand back a clone of the shared handle without
+        // re-running decode_contents.
+        if let Some(handle) = self.object_cache.get(&index).cloned() {
+            return handle.downcast::<T>()
+                          .map_err(|_| self.decoder.error("object cache type mismatch"));
+        }
 
         let address = self.object_table[object_table_index as usize];
 
         let current_position = self.decoder.position();
-        self.decoder.set_position(address);
+        self.decoder.set_position(address)?;
+
+        let object: Rc<T> = Rc::new(DecodableObject::decode_contents(self)?);
 
-        let object = DecodableObject::decode_contents(self);
+        // Remember the handle before returning so later (non-cyclic) references
+        // to the same index reuse it rather than decoding again.
+        self.object_cache.insert(index, object.clone() as Rc<dyn Any>);
 
-        self.decoder.set_position(current_position);
+        self.decoder.set_position(current_position)?;
 
-        object
+        Ok(object)
     }
-}
 
+    /// Resolve an interned index written by `emit_interned` back to its value
+    /// from the loaded interning table.
+    pub fn read_interned<T: Interned>(&mut self) -> Result<T, E> {
+        let index = self.decoder.read_uleb128()? as usize;
 
-pub struct DecodingSession<'ctx, DCX> {
-    context: DecodingContext<'ctx, DCX>
-}
+        if index >= self.interned_blobs.len() {
+            return Err(self.decoder.error("interned value index out of bounds"));
+        }
 
-impl<'ctx, DCX> DecodingSession<'ctx, DCX> {
+        Ok(T::from_intern_bytes(&self.interned_blobs[index]))
+    }
 
-    pub fn decode<T: Decodable<DCX>>(&mut self) -> T {
-        Decodable::decode(&mut self.context)
+    /// Convenience wrapper around `read_interned` for string leaves.
+    pub fn read_interned_str(&mut self) -> Result<String, E> {
+        self.read_interned::<String>()
     }
-}
 
+    /// Counterpart to `EncodingContext::encode_shorthand`. Reads the leading
+    /// position word: if the shorthand bit is set it seeks to the recorded
+    /// position, decodes the inline value there, and restores the position;
+    /// otherwise the value was written inline right here.
+    pub fn decode_shorthand<T: Decodable<DCX, E>>(&mut self) -> Result<T, E> {
+        let leading = self.decoder.read_uleb128()?;
 
-//=-----------------------------------------------------------------------------
-// TEST
-//=-----------------------------------------------------------------------------
+        if leading & SHORTHAND_BIT != 0 {
+            let address = leading & !SHORTHAND_BIT;
 
-struct Ast {
-    id: u64
-}
+            let current_position = self.decoder.position();
+            self.decoder.set_position(address)?;
+
+            let value = self.decode_shorthand()?;
 
-impl<ECX> Encodable<ECX> for u64 {
-    fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX>) {
-        ecx.encoder().emit_u64(*self)
+            self.decoder.set_position(current_position)?;
+
+            Ok(value)
+        } else {
+            Decodable::decode(self)
+        }
     }
 }
 
-impl<DCX> Decodable<DCX> for u64 {
-    fn decode(context: &mut DecodingContext<DCX>) -> u64 {
-        context.decoder().read_u64()
+
+pub struct DecodingSession<'ctx, DCX, E> {
+    context: DecodingContext<'ctx, DCX, E>
+}
+
+impl<'ctx, DCX, E> DecodingSession<'ctx, DCX, E> {
+
+    /// Open a blob finalized by `EncodingSession::finalize`. Seeks to the end,
+    /// reads the fixed-size footer, validates the `four_cc` and magic word,
+    /// then seeks to the object table and reads it back, yielding a session
+    /// ready to `decode` from.
+    pub fn open(decoder: &'ctx mut dyn Decoder<Error = E>,
+                four_cc: [u8; 4],
+                extra: DCX)
+        -> Result<DecodingSession<'ctx, DCX, E>, E>
+    {
+        let end = decoder.len();
+        if end < FOOTER_SIZE {
+            return Err(decoder.error("blob too short"));
+        }
+        decoder.set_position(end - FOOTER_SIZE)?;
+
+        let object_entry_count = decoder.read_u64()?;
+        let object_table_address = decoder.read_u64()?;
+        let interned_entry_count = decoder.read_u64()?;
+        let interning_table_address = decoder.read_u64()?;
+
+        let mut found_four_cc = [0u8; 4];
+        for byte in &mut found_four_cc {
+            *byte = decoder.read_u8()?;
+        }
+
+        if decoder.read_u32()? != MAGIC {
+            return Err(decoder.error("bad magic word"));
+        }
+
+        if found_four_cc != four_cc {
+            return Err(decoder.error("four_cc mismatch"));
+        }
+
+        decoder.set_position(object_table_address)?;
+        let mut object_table = Vec::with_capacity(object_entry_count as usize);
+        for _ in 0 .. object_entry_count {
+            object_table.push(decoder.read_u64()?);
+        }
+
+        decoder.set_position(interning_table_address)?;
+        let mut interned_blobs = Vec::with_capacity(interned_entry_count as usize);
+        for _ in 0 .. interned_entry_count {
+            interned_blobs.push(decoder.read_bytes()?);
+        }
+
+        // Position at the start of the body so the first `decode` reads the
+        // root value that was written there.
+        decoder.set_position(0)?;
+
+        Ok(DecodingSession {
+            context: DecodingContext {
+                decoder,
+                object_table,
+                object_cache: HashMap::new(),
+                interned_blobs,
+                extra,
+            }
+        })
+    }
+
+    pub fn decode<T: Decodable<DCX, E>>(&mut self) -> Result<T, E> {
+        Decodable::decode(&mut self.context)
     }
 }
 
-impl<ECX> Encodable<ECX> for Ast {
-    fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX>) {
-        Encodable::encode(&self.id, ecx)
+
+//=-----------------------------------------------------------------------------
+// PRIMITIVE IMPLS
+//=-----------------------------------------------------------------------------
+
+impl<ECX, E> Encodable<ECX, E> for u64 {
+    fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX, E>)
+        -> Result<(), E> {
+        ecx.encoder().emit_uleb128(*self)
     }
 }
 
-impl<DCX> Decodable<DCX> for Ast {
-    fn decode(context: &mut DecodingContext<DCX>) -> Ast {
-        Ast { id: Decodable::decode(context) }
+impl<DCX, E> Decodable<DCX, E> for u64 {
+    fn decode(context: &mut DecodingContext<DCX, E>) -> Result<u64, E> {
+        context.decoder().read_uleb128()
     }
 }
 
-impl<T: Encodable<ECX>, ECX> Encodable<ECX> for Option<T> {
-    fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX>) {
+impl<T: Encodable<ECX, E>, ECX, E> Encodable<ECX, E> for Option<T> {
+    fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX, E>)
+        -> Result<(), E> {
         match *self {
             None => {
-                ecx.encoder().emit_u32(0)
+                ecx.encoder().emit_bool(false)
             }
             Some(ref value) => {
-                ecx.encoder.emit_u32(1);
+                ecx.encoder().emit_bool(true)?;
                 Encodable::encode(value, ecx)
             }
         }
     }
 }
 
-impl<T: Decodable<DCX>, DCX> Decodable<DCX> for Option<T> {
-    fn decode(context: &mut DecodingContext<DCX>) -> Option<T> {
-        let disr = context.decoder().read_u32();
-        if disr == 0 {
-            None
+impl<T: Decodable<DCX, E>, DCX, E> Decodable<DCX, E> for Option<T> {
+    fn decode(context: &mut DecodingContext<DCX, E>) -> Result<Option<T>, E> {
+        if !context.decoder().read_bool()? {
+            Ok(None)
         }
         else {
-            Some(Decodable::decode(context))
+            Ok(Some(Decodable::decode(context)?))
         }
     }
 }
 
-struct Ty<'tcx> {
-    id: u64,
-    ast: Ast,
-    sub_ty: Option<&'tcx Ty<'tcx>>
-}
 
-trait TyRestoreContext<'tcx> {
-    fn create_interned(&self, id: u64, ast: Ast, sub_ty: Option<&'tcx Ty<'tcx>>) -> &'tcx Ty<'tcx>;
-}
+//=-----------------------------------------------------------------------------
+// TESTS
+//=-----------------------------------------------------------------------------
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
 
-impl<'tcx, DCX: TyRestoreContext<'tcx>> Decodable<DCX> for &'tcx Ty<'tcx> {
-    fn decode(context: &mut DecodingContext<DCX>) -> &'tcx Ty<'tcx> {
-        let id = Decodable::decode(context);
-        let ast = Decodable::decode(context);
-        let sub_ty = Decodable::decode(context);
+    //=- A minimal in-memory codec ---------------------------------------------
 
-        context.extra.create_interned(id, ast, sub_ty)
+    /// Encoder that appends to a shared byte buffer so the bytes survive the
+    /// `finalize` that consumes the boxed encoder.
+    struct VecEncoder {
+        bytes: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Encoder for VecEncoder {
+        type Error = String;
+
+        fn position(&self) -> u64 {
+            self.bytes.borrow().len() as u64
+        }
+
+        fn finalize(self: Box<Self>) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn emit_u8(&mut self, value: u8) -> Result<(), String> {
+            self.bytes.borrow_mut().push(value);
+            Ok(())
+        }
+    }
+
+    /// Decoder over an owned byte buffer with a seekable cursor.
+    struct VecDecoder {
+        bytes: Vec<u8>,
+        position: usize,
+    }
+
+    impl Decoder for VecDecoder {
+        type Error = String;
+
+        fn error(&mut self, err: &str) -> String {
+            err.to_string()
+        }
+
+        fn set_position(&mut self, position: u64) -> Result<(), String> {
+            if position as usize > self.bytes.len() {
+                return Err("seek past end".to_string());
+            }
+            self.position = position as usize;
+            Ok(())
+        }
+
+        fn position(&self) -> u64 {
+            self.position as u64
+        }
+
+        fn len(&self) -> u64 {
+            self.bytes.len() as u64
+        }
+
+        fn read_u8(&mut self) -> Result<u8, String> {
+            match self.bytes.get(self.position) {
+                Some(&byte) => {
+                    self.position += 1;
+                    Ok(byte)
+                }
+                None => Err("unexpected end of input".to_string()),
+            }
+        }
+    }
+
+    const FOUR_CC: [u8; 4] = *b"TEST";
+
+    /// Encode a root value through a session and return the finalized blob.
+    fn encode_blob<T: Encodable<(), String>>(value: &T) -> Vec<u8> {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut session = EncodingSession::new(VecEncoder { bytes: buffer.clone() }, ());
+        session.encode(value).unwrap();
+        session.finalize(FOUR_CC).unwrap();
+        // The binding drops the `Ref` borrow before `buffer`, which returning
+        // the clone directly would not.
+        let bytes = buffer.borrow().clone();
+        bytes
+    }
+
+    //=- Primitive / LEB128 round-trips ----------------------------------------
+
+    #[test]
+    fn leb128_and_primitive_roundtrip() {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut encoder = VecEncoder { bytes: buffer.clone() };
+
+        encoder.emit_uleb128(0).unwrap();
+        encoder.emit_uleb128(300).unwrap();
+        encoder.emit_uleb128(u64::MAX).unwrap();
+        encoder.emit_ileb128(-1).unwrap();
+        encoder.emit_ileb128(i64::MIN).unwrap();
+        encoder.emit_u32(0xDEAD_BEEF).unwrap();
+        encoder.emit_bool(true).unwrap();
+        encoder.emit_f64(1.5).unwrap();
+        encoder.emit_str("hello").unwrap();
+
+        let bytes = buffer.borrow().clone();
+        let mut decoder = VecDecoder { bytes, position: 0 };
+
+        assert_eq!(decoder.read_uleb128().unwrap(), 0);
+        assert_eq!(decoder.read_uleb128().unwrap(), 300);
+        assert_eq!(decoder.read_uleb128().unwrap(), u64::MAX);
+        assert_eq!(decoder.read_ileb128().unwrap(), -1);
+        assert_eq!(decoder.read_ileb128().unwrap(), i64::MIN);
+        assert_eq!(decoder.read_u32().unwrap(), 0xDEAD_BEEF);
+        assert!(decoder.read_bool().unwrap());
+        assert_eq!(decoder.read_f64().unwrap(), 1.5);
+        assert_eq!(decoder.read_str().unwrap(), "hello");
+    }
+
+    #[test]
+    fn overlong_leb128_is_rejected() {
+        // Eleven continuation bytes never terminate within a u64.
+        let bytes = vec![0x80u8; 11];
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        assert!(decoder.read_uleb128().is_err());
+    }
+
+    #[test]
+    fn open_rejects_short_blob() {
+        let mut decoder = VecDecoder { bytes: vec![0u8; 4], position: 0 };
+        let result = DecodingSession::<(), String>::open(&mut decoder, FOUR_CC, ());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn open_rejects_wrong_four_cc() {
+        let bytes = encode_blob(&7u64);
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let result = DecodingSession::<(), String>::open(&mut decoder, *b"NOPE", ());
+        assert!(result.is_err());
+    }
+
+    //=- finalize -> open round-trip -------------------------------------------
+
+    #[test]
+    fn finalize_open_roundtrip() {
+        let bytes = encode_blob(&0xCAFEu64);
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let mut session = DecodingSession::<(), String>::open(&mut decoder, FOUR_CC, ()).unwrap();
+        let value: u64 = session.decode().unwrap();
+        assert_eq!(value, 0xCAFE);
+    }
+
+    //=- Shorthand dedup -------------------------------------------------------
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    struct Leaf(u64);
+
+    impl Encodable<(), String> for Leaf {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, (), String>)
+            -> Result<(), String> {
+            ecx.encoder().emit_uleb128(self.0)
+        }
+    }
+
+    impl Decodable<(), String> for Leaf {
+        fn decode(context: &mut DecodingContext<(), String>) -> Result<Leaf, String> {
+            Ok(Leaf(context.decoder().read_uleb128()?))
+        }
+    }
+
+    struct ShorthandDoc {
+        a: Leaf,
+        b: Leaf,
+    }
+
+    impl Encodable<(), String> for ShorthandDoc {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, (), String>)
+            -> Result<(), String> {
+            ecx.encode_shorthand(&self.a)?;
+            ecx.encode_shorthand(&self.b)
+        }
+    }
+
+    struct DecodedShorthandDoc {
+        a: Leaf,
+        b: Leaf,
+    }
+
+    impl Decodable<(), String> for DecodedShorthandDoc {
+        fn decode(context: &mut DecodingContext<(), String>) -> Result<DecodedShorthandDoc, String> {
+            Ok(DecodedShorthandDoc {
+                a: context.decode_shorthand()?,
+                b: context.decode_shorthand()?,
+            })
+        }
+    }
+
+    #[test]
+    fn shorthand_backreference_roundtrip() {
+        // a and b are equal, so b should be emitted as a backreference to a.
+        let doc = ShorthandDoc { a: Leaf(99), b: Leaf(99) };
+        let bytes = encode_blob(&doc);
+
+        // a is written as a leading tag word (0) followed by its inline value,
+        // so b's leading word starts at offset 2 and, being a backreference,
+        // must carry the shorthand bit. Without dedup it would be another
+        // bare tag word with the bit clear.
+        let mut raw = VecDecoder { bytes: bytes.clone(), position: 2 };
+        assert!(raw.read_uleb128().unwrap() & SHORTHAND_BIT != 0,
+                "b should be a backreference, not a second inline copy");
+
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let mut session =
+            DecodingSession::<(), String>::open(&mut decoder, FOUR_CC, ()).unwrap();
+        let decoded: DecodedShorthandDoc = session.decode().unwrap();
+
+        assert_eq!(decoded.a, Leaf(99));
+        assert_eq!(decoded.b, Leaf(99));
+    }
+
+    #[test]
+    fn shorthand_distinct_values_are_not_aliased() {
+        let doc = ShorthandDoc { a: Leaf(1), b: Leaf(2) };
+        let bytes = encode_blob(&doc);
+
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let mut session =
+            DecodingSession::<(), String>::open(&mut decoder, FOUR_CC, ()).unwrap();
+        let decoded: DecodedShorthandDoc = session.decode().unwrap();
+
+        assert_eq!(decoded.a, Leaf(1));
+        assert_eq!(decoded.b, Leaf(2));
+    }
+
+    //=- Shared-object memoization ---------------------------------------------
+
+    struct Shared {
+        payload: u64,
+    }
+
+    impl Encodable<(), String> for Shared {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, (), String>)
+            -> Result<(), String> {
+            ecx.encode_object(self)
+        }
+    }
+
+    impl EncodableObject<(), String> for Shared {
+        fn object_uid(&self) -> ObjectUid {
+            ObjectUid(self.payload)
+        }
+
+        fn encode_contents<'a, 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, (), String>)
+            -> Result<(), String>
+            where 'a: 'ecx {
+            Encodable::encode(&self.payload, ecx)
+        }
+    }
+
+    impl Decodable<(), String> for Shared {
+        fn decode(context: &mut DecodingContext<(), String>) -> Result<Shared, String> {
+            Shared::decode_contents(context)
+        }
+    }
+
+    impl DecodableObject<(), String> for Shared {
+        fn decode_contents(context: &mut DecodingContext<(), String>) -> Result<Shared, String> {
+            Ok(Shared { payload: context.decoder().read_uleb128()? })
+        }
+    }
+
+    struct Holder<'a> {
+        left: &'a Shared,
+        right: &'a Shared,
+    }
+
+    impl<'h> Encodable<(), String> for Holder<'h> {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, (), String>)
+            -> Result<(), String> {
+            ecx.encode_object(self.left)?;
+            ecx.encode_object(self.right)
+        }
+    }
+
+    struct DecodedHolder {
+        left: Rc<Shared>,
+        right: Rc<Shared>,
+    }
+
+    impl Decodable<(), String> for DecodedHolder {
+        fn decode(context: &mut DecodingContext<(), String>) -> Result<DecodedHolder, String> {
+            Ok(DecodedHolder {
+                left: context.decode_object::<Shared>()?,
+                right: context.decode_object::<Shared>()?,
+            })
+        }
     }
-}
 
-impl<'tcx, DCX: TyRestoreContext<'tcx>> Decodable<DCX> for Ty<'tcx> {
-    fn decode(context: &mut DecodingContext<DCX>) -> Ty<'tcx> {
-        let id = Decodable::decode(context);
-        let ast = Decodable::decode(context);
-        let sub_ty = Decodable::decode(context);
+    #[test]
+    fn shared_object_is_decoded_once() {
+        let shared = Shared { payload: 7 };
+        let holder = Holder { left: &shared, right: &shared };
+        let bytes = encode_blob(&holder);
+
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let mut session =
+            DecodingSession::<(), String>::open(&mut decoder, FOUR_CC, ()).unwrap();
+        let decoded: DecodedHolder = session.decode().unwrap();
+
+        assert_eq!(decoded.left.payload, 7);
+        assert_eq!(decoded.right.payload, 7);
+        // Both references resolve to the same shared handle.
+        assert!(Rc::ptr_eq(&decoded.left, &decoded.right));
+    }
+
+    //=- Interning -------------------------------------------------------------
+
+    struct InternedDoc {
+        name: String,
+        also: String,
+        n: u64,
+    }
+
+    impl Encodable<(), String> for InternedDoc {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, (), String>)
+            -> Result<(), String> {
+            ecx.emit_interned_str(&self.name)?;
+            ecx.emit_interned_str(&self.also)?;
+            Encodable::encode(&self.n, ecx)
+        }
+    }
+
+    struct DecodedInternedDoc {
+        name: String,
+        also: String,
+        n: u64,
+    }
+
+    impl Decodable<(), String> for DecodedInternedDoc {
+        fn decode(context: &mut DecodingContext<(), String>) -> Result<DecodedInternedDoc, String> {
+            Ok(DecodedInternedDoc {
+                name: context.read_interned_str()?,
+                also: context.read_interned_str()?,
+                n: Decodable::decode(context)?,
+            })
+        }
+    }
+
+    #[test]
+    fn interned_strings_roundtrip() {
+        let doc = InternedDoc {
+            name: "core::option".to_string(),
+            also: "core::option".to_string(),
+            n: 5,
+        };
+
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut session = EncodingSession::new(VecEncoder { bytes: buffer.clone() }, ());
+        session.encode(&doc).unwrap();
+        // name and also are equal, so interning must store a single shared blob
+        // that both uses reference by index.
+        assert_eq!(session.interned_blobs.len(), 1);
+        session.finalize(FOUR_CC).unwrap();
+        let bytes = buffer.borrow().clone();
+
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let mut session =
+            DecodingSession::<(), String>::open(&mut decoder, FOUR_CC, ()).unwrap();
+        let decoded: DecodedInternedDoc = session.decode().unwrap();
+
+        assert_eq!(decoded.name, "core::option");
+        assert_eq!(decoded.also, "core::option");
+        assert_eq!(decoded.n, 5);
+    }
+
+    //=- The Ty / sub_ty chain from the original scaffolding -------------------
 
-        Ty {
-            id: id,
-            ast: ast,
-            sub_ty: sub_ty
+    struct Ast {
+        id: u64,
+    }
+
+    impl<ECX, E> Encodable<ECX, E> for Ast {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX, E>)
+            -> Result<(), E> {
+            Encodable::encode(&self.id, ecx)
         }
     }
+
+    impl<DCX, E> Decodable<DCX, E> for Ast {
+        fn decode(context: &mut DecodingContext<DCX, E>) -> Result<Ast, E> {
+            Ok(Ast { id: Decodable::decode(context)? })
+        }
+    }
+
+    struct Ty<'tcx> {
+        id: u64,
+        ast: Ast,
+        sub_ty: Option<&'tcx Ty<'tcx>>,
+    }
+
+    trait TyRestoreContext<'tcx> {
+        fn create_interned(&self, id: u64, ast: Ast, sub_ty: Option<&'tcx Ty<'tcx>>)
+            -> &'tcx Ty<'tcx>;
+    }
+
+    impl<'tcx, ECX, E> Encodable<ECX, E> for &'tcx Ty<'tcx> {
+        fn encode<'ecx, 'a: 'ecx>(&'a self, ecx: &mut EncodingContext<'ecx, ECX, E>)
+            -> Result<(), E> {
+            Encodable::encode(&self.id, ecx)?;
+            Encodable::encode(&self.ast, ecx)?;
+            Encodable::encode(&self.sub_ty, ecx)
+        }
+    }
+
+    impl<'tcx, DCX: TyRestoreContext<'tcx>, E> Decodable<DCX, E> for &'tcx Ty<'tcx> {
+        fn decode(context: &mut DecodingContext<DCX, E>) -> Result<&'tcx Ty<'tcx>, E> {
+            let id = Decodable::decode(context)?;
+            let ast = Decodable::decode(context)?;
+            let sub_ty = Decodable::decode(context)?;
+
+            Ok(context.extra.create_interned(id, ast, sub_ty))
+        }
+    }
+
+    /// Restore context that interns each decoded `Ty` by leaking it, which is
+    /// enough to hand out `&'tcx` references in a test.
+    struct LeakyArena;
+
+    impl<'tcx> TyRestoreContext<'tcx> for LeakyArena {
+        fn create_interned(&self, id: u64, ast: Ast, sub_ty: Option<&'tcx Ty<'tcx>>)
+            -> &'tcx Ty<'tcx> {
+            Box::leak(Box::new(Ty { id, ast, sub_ty }))
+        }
+    }
+
+    #[test]
+    fn ty_chain_roundtrip() {
+        let leaf = Ty { id: 1, ast: Ast { id: 10 }, sub_ty: None };
+        let root = Ty { id: 2, ast: Ast { id: 20 }, sub_ty: Some(&leaf) };
+        let root_ref: &Ty = &root;
+
+        let bytes = encode_blob(&root_ref);
+
+        let mut decoder = VecDecoder { bytes, position: 0 };
+        let mut session =
+            DecodingSession::<LeakyArena, String>::open(&mut decoder, FOUR_CC, LeakyArena).unwrap();
+        let decoded: &Ty = session.decode().unwrap();
+
+        assert_eq!(decoded.id, 2);
+        assert_eq!(decoded.ast.id, 20);
+        let sub = decoded.sub_ty.unwrap();
+        assert_eq!(sub.id, 1);
+        assert_eq!(sub.ast.id, 10);
+        assert!(sub.sub_ty.is_none());
+    }
 }